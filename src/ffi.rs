@@ -8,12 +8,41 @@ use std::ptr;
 #[doc(hidden)]
 pub use serde_bytes::ByteBuf;
 
+#[cfg(feature = "serde_derive_ffi_fam")]
+use vmm_sys_util::fam::{FamStruct, FamStructWrapper};
+
 /// Trait to get size information about an FFI object.
 pub trait SizeofFamStruct {
     /// Get actual size of an FFI object.
     fn size_of(&self) -> usize;
 }
 
+/// Trait for FFI objects that can (de)serialize their own bytes in a fixed, host-independent
+/// endianness, instead of the raw byte-for-byte copy [`serialize_ffi`]/[`deserialize_ffi`] do.
+///
+/// Every scalar field of the fixed-size head, and of each flexible-array entry, should be
+/// converted with `to_le`/`from_le`, and any inter-field padding bytes zeroed instead of copied
+/// verbatim. [`SizeofFamStruct::size_of`] stays the authority for how many flexible-array entries
+/// follow the head. There is currently no derive that generates this impl automatically (unlike
+/// `SerializeFfi`/`DeserializeFfi`); implement it by hand, as the tests in this module do.
+pub trait CanonicalFfi: SizeofFamStruct {
+    /// Write `self` into `buf` in canonical form.
+    ///
+    /// [`serialize_ffi_canonical`] passes a zeroed `buf` of exactly `self.size_of()` bytes.
+    /// [`serialize_ffi_fam_canonical`] instead passes each `Vec<T>` chunk, head and entries alike,
+    /// its own zeroed `mem::size_of::<Self>()`-byte slice.
+    fn write_canonical(&self, buf: &mut [u8]);
+
+    /// Read `Self` from the canonical bytes in `buf`.
+    ///
+    /// [`deserialize_ffi_canonical`] passes the whole blob, which holds at least the fixed-size
+    /// head in canonical form; implementations must not read more flexible-array entries than
+    /// `buf` holds. [`deserialize_ffi_fam_canonical`] instead calls this once per
+    /// `mem::size_of::<Self>()`-byte chunk, head and entries alike; the last chunk is shorter when
+    /// the blob's length is not a multiple of that size.
+    fn read_canonical(buf: &[u8]) -> Self;
+}
+
 #[macro_export]
 macro_rules! serde_ffi_fam_impl {
     ($struct: ty, $field: ident, $entry: ty) => {
@@ -88,6 +117,128 @@ where
     }
 }
 
+/// Serialize an FFI object into `ByteBuf` using canonical (host-independent) encoding.
+///
+/// Unlike [`serialize_ffi`], which does a raw `ptr::copy` of the struct's bytes and so bakes in
+/// the host's endianness and copies uninitialized padding, this emits every scalar field in a
+/// fixed endianness and zeroes padding, so a VM snapshot serializes identically regardless of
+/// host byte order and reproducibly diffs byte-for-byte across runs.
+pub fn serialize_ffi_canonical<T: CanonicalFfi>(something: &T) -> ByteBuf {
+    let mut serialized_self = vec![0u8; something.size_of()];
+    something.write_canonical(&mut serialized_self);
+    ByteBuf::from(serialized_self)
+}
+
+/// Deserialize an FFI object from `ByteBuf` produced by [`serialize_ffi_canonical`].
+///
+/// The decoded count field, via [`SizeofFamStruct::size_of`], stays the authority for how long
+/// the blob should be; a canonical length that disagrees with `serialized`'s actual length is
+/// rejected rather than silently truncated or over-read.
+pub fn deserialize_ffi_canonical<T>(serialized: ByteBuf) -> std::result::Result<T, (usize, usize)>
+where
+    T: CanonicalFfi + Default,
+{
+    let data = serialized.into_vec();
+    if data.len() < mem::size_of::<T>() {
+        Err((mem::size_of::<T>(), data.len()))
+    } else {
+        let something = T::read_canonical(&data);
+        if something.size_of() != data.len() {
+            Err((something.size_of(), data.len()))
+        } else {
+            Ok(something)
+        }
+    }
+}
+
+/// Serialize a FAM-bearing FFI object into `ByteBuf` using canonical (host-independent) encoding.
+///
+/// Unlike [`serialize_ffi_canonical`], which only encodes the fixed-size head, `something` here is
+/// the same `Vec<T>`-of-chunks layout [`deserialize_ffi_fam`]/[`deserialize_ffi_fam_canonical`]
+/// produce: the fixed-size head at index 0, followed by each flexible-array entry reinterpreted as
+/// a `T`-sized chunk. Every chunk, head and entries alike, is encoded with
+/// [`CanonicalFfi::write_canonical`].
+pub fn serialize_ffi_fam_canonical<T: CanonicalFfi>(something: &[T]) -> ByteBuf {
+    let mut serialized_self = vec![0u8; something[0].size_of()];
+    let chunk_size = mem::size_of::<T>();
+    for (entry, chunk) in something.iter().zip(serialized_self.chunks_mut(chunk_size)) {
+        entry.write_canonical(chunk);
+    }
+    ByteBuf::from(serialized_self)
+}
+
+/// Deserialize a FAM-bearing FFI object from `ByteBuf` produced by [`serialize_ffi_fam_canonical`].
+///
+/// Mirrors [`deserialize_ffi_fam`]: returns the fixed-size head at index 0 followed by each
+/// flexible-array entry, every chunk canonically decoded via [`CanonicalFfi::read_canonical`]. The
+/// head's own count field, via [`SizeofFamStruct::size_of`], stays the authority for how many
+/// entries follow; a canonical length that disagrees with `serialized`'s actual length is rejected.
+pub fn deserialize_ffi_fam_canonical<T>(
+    serialized: ByteBuf,
+) -> std::result::Result<Vec<T>, (usize, usize)>
+where
+    T: CanonicalFfi + Default,
+{
+    let data = serialized.into_vec();
+    if data.len() < mem::size_of::<T>() {
+        return Err((mem::size_of::<T>(), data.len()));
+    }
+    let chunk_size = mem::size_of::<T>();
+    let head = T::read_canonical(&data[..chunk_size]);
+    if head.size_of() != data.len() {
+        return Err((head.size_of(), data.len()));
+    }
+
+    let mut entries = Vec::with_capacity(data.len() / chunk_size);
+    entries.push(head);
+    for chunk in data[chunk_size..].chunks(chunk_size) {
+        entries.push(T::read_canonical(chunk));
+    }
+    Ok(entries)
+}
+
+/// Serialize a `FamStructWrapper<T>` into `ByteBuf`.
+///
+/// `FamStructWrapper` already stores its fixed header and flexible-array entries contiguously, in
+/// the same `Vec<T>`-of-chunks layout `deserialize_ffi_fam` produces, so this just reuses
+/// [`serialize_ffi`] on the wrapper's head struct.
+///
+/// `T: FamStruct` is the caller's own responsibility: there is no derive for it here, only for
+/// `SizeofFamStruct` (`serde_ffi_fam_impl!`). A bindgen struct with an `__IncompleteArrayField`
+/// tail needs `len`/`set_len`/`max_len`/`as_slice`/`as_mut_slice` hand-written against that tail,
+/// as the tests in this module do; `serialize_fam_wrapper`/`deserialize_fam_wrapper` only remove
+/// the `Vec<T>` <-> `FamStructWrapper<T>` conversion step, not the `FamStruct` impl itself.
+#[cfg(feature = "serde_derive_ffi_fam")]
+pub fn serialize_fam_wrapper<T>(wrapper: &FamStructWrapper<T>) -> ByteBuf
+where
+    T: SizeofFamStruct + FamStruct + Default,
+{
+    serialize_ffi(wrapper.as_fam_struct_ref())
+}
+
+/// Deserialize a `FamStructWrapper<T>` from `ByteBuf`.
+///
+/// This is the canonical representation KVM/virtio code actually holds a bindgen FAM struct in,
+/// so callers no longer need to hand-roll the `Vec<T>` -> `FamStructWrapper<T>` conversion that
+/// [`deserialize_ffi_fam`] leaves as an exercise for the caller; the struct's own `nmsrs`-style
+/// length field, not the deserialized byte count, stays authoritative for the entry count.
+///
+/// As with [`serialize_fam_wrapper`], `T: FamStruct` itself is still the caller's own
+/// responsibility - see that function's docs.
+#[cfg(feature = "serde_derive_ffi_fam")]
+pub fn deserialize_fam_wrapper<T>(
+    serialized: ByteBuf,
+) -> std::result::Result<FamStructWrapper<T>, (usize, usize)>
+where
+    T: SizeofFamStruct + FamStruct + Default,
+{
+    let entries = deserialize_ffi_fam(serialized)?;
+    // SAFETY: `entries` is the raw `Vec<T>` layout produced by `deserialize_ffi_fam`, where
+    // `entries[0]`'s own FAM length field describes exactly the flexible-array data that follows
+    // it, which is what `from_raw` requires.
+    Ok(unsafe { FamStructWrapper::from_raw(entries) })
+}
+
 #[cfg(test)]
 mod tests {
     extern crate serde_json;
@@ -133,6 +284,102 @@ mod tests {
         assert_eq!(decoded.target_phys_addr, 5);
     }
 
+    #[cfg(feature = "serde_derive_ffi")]
+    #[test]
+    fn ffi_test_ffi_canonical() {
+        #[repr(C)]
+        #[derive(Debug, Default, PartialEq)]
+        pub struct kvm_memory_alias {
+            pub slot: u16,
+            // Two bytes of padding the host compiler inserts before `flags`; a raw byte copy
+            // would carry across whatever garbage happens to be here.
+            pub flags: u32,
+            pub guest_phys_addr: u64,
+        }
+
+        impl SizeofFamStruct for kvm_memory_alias {
+            fn size_of(&self) -> usize {
+                mem::size_of::<Self>()
+            }
+        }
+
+        impl CanonicalFfi for kvm_memory_alias {
+            fn write_canonical(&self, buf: &mut [u8]) {
+                buf[0..2].copy_from_slice(&self.slot.to_le_bytes());
+                buf[2..4].fill(0);
+                buf[4..8].copy_from_slice(&self.flags.to_le_bytes());
+                buf[8..16].copy_from_slice(&self.guest_phys_addr.to_le_bytes());
+            }
+
+            fn read_canonical(buf: &[u8]) -> Self {
+                kvm_memory_alias {
+                    slot: u16::from_le_bytes(buf[0..2].try_into().unwrap()),
+                    flags: u32::from_le_bytes(buf[4..8].try_into().unwrap()),
+                    guest_phys_addr: u64::from_le_bytes(buf[8..16].try_into().unwrap()),
+                }
+            }
+        }
+
+        let original = kvm_memory_alias {
+            slot: 1,
+            flags: 2,
+            guest_phys_addr: 3,
+        };
+
+        let serialized = serialize_ffi_canonical(&original);
+        assert_eq!(&serialized.as_ref()[2..4], &[0, 0]);
+        let decoded: kvm_memory_alias = deserialize_ffi_canonical(serialized).unwrap();
+        assert_eq!(decoded, original);
+
+        let truncated = ByteBuf::from(vec![0u8; mem::size_of::<kvm_memory_alias>() - 1]);
+        assert!(deserialize_ffi_canonical::<kvm_memory_alias>(truncated).is_err());
+    }
+
+    #[cfg(feature = "serde_derive_ffi")]
+    #[test]
+    fn ffi_test_ffi_fam_canonical() {
+        // `nmsrs`/`entry` are both `u32`, so this struct's own size (8 bytes) matches each
+        // trailing entry's size, the same chunking convention `deserialize_ffi_fam` relies on.
+        #[repr(C)]
+        #[derive(Debug, Default, Clone, Copy, PartialEq)]
+        pub struct kvm_msrs_canonical {
+            pub nmsrs: u32,
+            pub entry: u32,
+        }
+
+        impl SizeofFamStruct for kvm_msrs_canonical {
+            fn size_of(&self) -> usize {
+                self.nmsrs as usize * mem::size_of::<Self>() + mem::size_of::<Self>()
+            }
+        }
+
+        impl CanonicalFfi for kvm_msrs_canonical {
+            fn write_canonical(&self, buf: &mut [u8]) {
+                buf[0..4].copy_from_slice(&self.nmsrs.to_le_bytes());
+                buf[4..8].copy_from_slice(&self.entry.to_le_bytes());
+            }
+
+            fn read_canonical(buf: &[u8]) -> Self {
+                kvm_msrs_canonical {
+                    nmsrs: u32::from_le_bytes(buf[0..4].try_into().unwrap()),
+                    entry: u32::from_le_bytes(buf[4..8].try_into().unwrap()),
+                }
+            }
+        }
+
+        let original = vec![
+            kvm_msrs_canonical { nmsrs: 2, entry: 0 },
+            kvm_msrs_canonical { nmsrs: 0, entry: 10 },
+            kvm_msrs_canonical { nmsrs: 0, entry: 20 },
+        ];
+
+        let serialized = serialize_ffi_fam_canonical(&original);
+        let decoded: Vec<kvm_msrs_canonical> = deserialize_ffi_fam_canonical(serialized).unwrap();
+        assert_eq!(decoded, original);
+        assert_eq!(decoded[1].entry, 10);
+        assert_eq!(decoded[2].entry, 20);
+    }
+
     #[cfg(feature = "serde_derive_ffi")]
     #[test]
     fn ffi_test_ffi_fam_struct() {
@@ -171,9 +418,97 @@ mod tests {
         let ser = serde_json::to_string(&data[0]).unwrap();
         let mut deserializer = serde_json::Deserializer::from_str(&ser);
         let content: Vec<kvm_msrs> = kvm_msrs::deserialize(&mut deserializer).unwrap();
-        // let decoded: FamStructWrapper<kvm_msrs> = content.into();
+        // See `ffi_test_fam_wrapper_roundtrip` below for the `FamStructWrapper<kvm_msrs>`
+        // conversion, which additionally needs a `FamStruct` impl for `kvm_msrs`.
 
         assert_eq!(content[0].nmsrs, 1);
         assert_eq!(content[0].pad, 0);
     }
+
+    #[cfg(all(feature = "serde_derive_ffi", feature = "serde_derive_ffi_fam"))]
+    #[test]
+    fn ffi_test_fam_wrapper_roundtrip() {
+        #[repr(C)]
+        #[derive(Default, Debug, SerializeFfi, DeserializeFfi)]
+        pub struct __IncompleteArrayField<T>(::std::marker::PhantomData<T>, [T; 0]);
+        impl<T> __IncompleteArrayField<T> {
+            #[inline]
+            pub fn new() -> Self {
+                __IncompleteArrayField(::std::marker::PhantomData, [])
+            }
+
+            #[inline]
+            pub unsafe fn as_ptr(&self) -> *const T {
+                std::mem::transmute(self)
+            }
+
+            #[inline]
+            pub unsafe fn as_mut_ptr(&mut self) -> *mut T {
+                std::mem::transmute(self)
+            }
+
+            #[inline]
+            pub unsafe fn as_slice(&self, len: usize) -> &[T] {
+                std::slice::from_raw_parts(self.as_ptr(), len)
+            }
+
+            #[inline]
+            pub unsafe fn as_mut_slice(&mut self, len: usize) -> &mut [T] {
+                std::slice::from_raw_parts_mut(self.as_mut_ptr(), len)
+            }
+        }
+
+        #[repr(C)]
+        #[derive(Debug, Default, SerializeFfi, DeserializeFfiFam)]
+        pub struct kvm_msrs {
+            pub nmsrs: u32,
+            pub pad: u32,
+            pub entries: __IncompleteArrayField<u64>,
+        }
+
+        serde_ffi_fam_impl!(kvm_msrs, nmsrs, u64);
+
+        // SAFETY: `kvm_msrs` is `#[repr(C)]` and POD, and `nmsrs` always reflects the number of
+        // `u64` entries actually allocated after it, as required by `FamStruct`.
+        unsafe impl FamStruct for kvm_msrs {
+            type Entry = u64;
+
+            fn len(&self) -> usize {
+                self.nmsrs as usize
+            }
+
+            unsafe fn set_len(&mut self, len: usize) {
+                self.nmsrs = len as u32;
+            }
+
+            fn max_len() -> usize {
+                usize::MAX
+            }
+
+            fn as_slice(&self) -> &[u64] {
+                let len = self.len();
+                // SAFETY: `len` is exactly the number of `u64` entries `nmsrs` reports, and
+                // `FamStructWrapper` guarantees that many entries are allocated contiguously
+                // right after this header.
+                unsafe { self.entries.as_slice(len) }
+            }
+
+            fn as_mut_slice(&mut self) -> &mut [u64] {
+                let len = self.len();
+                // SAFETY: see `as_slice` above.
+                unsafe { self.entries.as_mut_slice(len) }
+            }
+        }
+
+        let mut wrapper: FamStructWrapper<kvm_msrs> = FamStructWrapper::new(2).unwrap();
+        // SAFETY: we're only writing entries, not changing `nmsrs`.
+        unsafe { wrapper.as_mut_fam_struct() }
+            .as_mut_slice()
+            .copy_from_slice(&[0x1111, 0x2222]);
+
+        let serialized = serialize_fam_wrapper(&wrapper);
+        let decoded: FamStructWrapper<kvm_msrs> = deserialize_fam_wrapper(serialized).unwrap();
+        assert_eq!(decoded.as_fam_struct_ref().nmsrs, 2);
+        assert_eq!(decoded.as_slice(), &[0x1111, 0x2222]);
+    }
 }
@@ -127,6 +127,32 @@
 //!    }
 //! }
 //! ```
+//!
+//! Code like KVM/virtio that holds such a struct as a [`vmm_sys_util::fam::FamStructWrapper`]
+//! rather than a raw `Vec<T>` can skip the manual conversion by enabling the
+//! `serde_derive_ffi_fam` feature and using [`serialize_fam_wrapper`]/[`deserialize_fam_wrapper`]
+//! instead of [`serialize_ffi`]/[`deserialize_ffi_fam`] directly.
+//!
+//! [`serialize_ffi`]/[`deserialize_ffi`] copy the struct's raw bytes, which bakes in the host's
+//! byte order and copies whatever uninitialized padding happens to be there. That's fatal when
+//! migrating a snapshot between hosts of different endianness, or when diffing snapshots for
+//! reproducibility. [`serialize_ffi_canonical`]/[`deserialize_ffi_canonical`] instead emit every
+//! scalar field in a fixed endianness with padding zeroed, by way of a [`CanonicalFfi`] impl;
+//! [`serialize_ffi_fam_canonical`]/[`deserialize_ffi_fam_canonical`] are their flexible-array
+//! counterparts, mirroring [`serialize_ffi`]/[`deserialize_ffi_fam`].
+//!
+//! # Stateful Serialization/Deserialization
+//! `Serialize`/`Deserialize` (de)serialize a value in isolation, but VM snapshot/live migration
+//! often needs to thread shared context through an entire object graph instead, e.g. a
+//! host->guest file descriptor table. The `SerializeState`/`DeserializeState` traits and their
+//! derive macros, gated by the `serde_derive_state` feature, support that use case and build on
+//! stable Rust. See the [`state`] module docs for details.
+
+// Lets `#[derive(SerializeState, DeserializeState)]`-generated code, which refers to this crate's
+// own items through the absolute path `::vmm_serde::...`, resolve from within this crate's own
+// `#[cfg(test)]` modules (the path otherwise only resolves from a downstream crate).
+#[cfg(all(test, feature = "serde_derive_state"))]
+extern crate self as vmm_serde;
 
 #[cfg(feature = "serde_derive")]
 #[doc(hidden)]
@@ -138,7 +164,21 @@ pub use serde::*;
 #[cfg(feature = "serde_derive_ffi")]
 mod ffi;
 #[cfg(feature = "serde_derive_ffi")]
-pub use ffi::{deserialize_ffi, deserialize_ffi_fam, serialize_ffi, ByteBuf, SizeofFamStruct};
+pub use ffi::{
+    deserialize_ffi, deserialize_ffi_canonical, deserialize_ffi_fam,
+    deserialize_ffi_fam_canonical, serialize_ffi, serialize_ffi_canonical,
+    serialize_ffi_fam_canonical, ByteBuf, CanonicalFfi, SizeofFamStruct,
+};
+#[cfg(feature = "serde_derive_ffi_fam")]
+pub use ffi::{deserialize_fam_wrapper, serialize_fam_wrapper};
+
+#[cfg(feature = "serde_derive_state")]
+mod state;
+#[cfg(feature = "serde_derive_state")]
+pub use state::{DeserializeState, SerializeState, Unseeded};
+#[cfg(feature = "serde_derive_state")]
+#[doc(hidden)]
+pub use state::{DeserializeStateSeed, SerializeStateField};
 
 #[doc(hidden)]
 pub use vmm_serde_impl::*;
@@ -197,4 +237,16 @@ mod tests {
             state: u32,
         }
     }
+
+    #[cfg(feature = "serde_derive_state")]
+    #[test]
+    #[allow(dead_code)]
+    fn test_state_derive() {
+        #[derive(SerializeState, DeserializeState)]
+        pub(super) struct VmmObject8 {
+            state: u32,
+            #[serde_state(skip)]
+            name: String,
+        }
+    }
 }
@@ -0,0 +1,523 @@
+// Copyright (C) 2019 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Stateful serialization: thread a caller-supplied `Seed` through every (de)serialized value.
+//!
+//! `Serialize`/`Deserialize` treat every value in isolation, but VM snapshot, live upgrading and
+//! live migration often need to carry shared context across an entire object graph: a
+//! host->guest file descriptor table, a memory-region remapping table, or a format version.
+//! `SerializeState<Seed>` and `DeserializeState<'de, Seed>` are the stateful counterparts of
+//! `Serialize`/`Deserialize`: they take the same serializer/deserializer, plus a `seed` that is
+//! forwarded, unchanged in identity, to every nested field. `#[derive(SerializeState,
+//! DeserializeState)]` (de)serializes a struct's fields in declaration order as a fixed-length
+//! tuple, calling `serialize_state`/`deserialize_state` with the same `seed` for every field.
+//! Unlike serde's own struct derive, it writes no field names, and the generated visitor accepts
+//! only a sequence, not a map.
+//!
+//! The critical invariant is that serialize and deserialize visit fields in identical order, so
+//! that the seed's internal counters/tables stay in sync: a snapshot subsystem can intern a
+//! `RawFd` as an index into a table on the way out, then re-resolve that index against the
+//! restoring VM's fresh table on the way in. A field that doesn't need the seed can opt out with
+//! `#[serde_state(skip)]`, in which case it's (de)serialized with plain `Serialize`/`Deserialize`
+//! instead, bypassing `SerializeState`/`DeserializeState` for that field entirely.
+//!
+//! ## Example
+//! ```
+//! # extern crate vmm_serde;
+//! # use vmm_serde::{DeserializeState, SerializeState};
+//!
+//! #[derive(SerializeState, DeserializeState)]
+//! struct Device {
+//!     fd_index: u32,
+//!     #[serde_state(skip)]
+//!     name: String,
+//! }
+//! ```
+
+use serde::de::{DeserializeSeed, Deserializer, SeqAccess, Visitor};
+use serde::ser::{SerializeSeq, SerializeTuple};
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+use std::marker::PhantomData;
+
+/// Serialize a value, threading `seed` through every nested field.
+///
+/// See the [module docs](self) for the rationale. Impls are provided for the common leaf types
+/// (integers, floats, `bool`, `char`, `String`, `()`) and for containers (`Vec<T>`, `Option<T>`,
+/// `Box<T>`, tuples), which forward `seed` to each element in turn.
+///
+/// There is deliberately no blanket `impl<Seed, T: Serialize> SerializeState<Seed> for T`: that
+/// would need specialization to coexist with the container impls above on stable Rust (two
+/// `Vec<T>` impls would otherwise overlap), and with it, every `Serialize` type would silently
+/// ignore `seed` instead of being forced to say so. So any other `Serialize` type (a nested
+/// struct, an enum, `[u8; N]`, `HashMap`, ...) needs an explicit opt-in instead:
+/// - as a direct, non-skipped `#[derive(SerializeState)]` field: mark it `#[serde_state(skip)]`.
+/// - nested inside a `Vec<T>`/`Option<T>`/tuple, where `#[serde_state(skip)]` doesn't apply: wrap
+///   it in [`Unseeded<T>`].
+pub trait SerializeState<Seed: ?Sized> {
+    /// Serialize `self` into `serializer`, forwarding `seed` to every nested value.
+    fn serialize_state<S>(&self, serializer: S, seed: &Seed) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer;
+}
+
+/// Deserialize a value, threading `seed` through every nested field.
+///
+/// See the [module docs](self) for the rationale, and [`SerializeState`]'s docs for why there is
+/// no blanket `impl<Seed, T: Deserialize> DeserializeState<Seed> for T` and what to do instead for
+/// a plain-`Deserialize` type: `#[serde_state(skip)]` for a direct struct field, or
+/// [`Unseeded<T>`] nested inside a `Vec<T>`/`Option<T>`/tuple.
+pub trait DeserializeState<'de, Seed: ?Sized>: Sized {
+    /// Deserialize `Self` from `deserializer`, forwarding `seed` to every nested value.
+    fn deserialize_state<D>(seed: &mut Seed, deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>;
+}
+
+// Leaf types compose for free: the seed is simply ignored. Unlike a blanket `impl<T: Serialize>`,
+// this is a finite, non-overlapping set of concrete types, so it coexists with the generic
+// container impls below on stable Rust without specialization.
+macro_rules! state_leaf_impl {
+    ($($ty:ty),+ $(,)?) => {
+        $(
+            impl<Seed: ?Sized> SerializeState<Seed> for $ty {
+                fn serialize_state<S>(&self, serializer: S, _seed: &Seed) -> Result<S::Ok, S::Error>
+                where
+                    S: Serializer,
+                {
+                    Serialize::serialize(self, serializer)
+                }
+            }
+
+            impl<'de, Seed: ?Sized> DeserializeState<'de, Seed> for $ty {
+                fn deserialize_state<D>(_seed: &mut Seed, deserializer: D) -> Result<Self, D::Error>
+                where
+                    D: Deserializer<'de>,
+                {
+                    Deserialize::deserialize(deserializer)
+                }
+            }
+        )+
+    };
+}
+
+state_leaf_impl!(
+    (),
+    bool,
+    char,
+    f32,
+    f64,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    String,
+);
+
+/// Opt-in wrapper letting a plain `Serialize`/`Deserialize` type be threaded through
+/// `SerializeState`/`DeserializeState` as-is, ignoring `seed`.
+///
+/// See the [`SerializeState`] docs for when this is needed instead of `#[serde_state(skip)]`:
+/// namely, inside a `Vec<T>`/`Option<T>`/tuple, where `skip` (a field-level derive attribute)
+/// doesn't apply. `Unseeded` derefs to `T` and converts to/from `T` via [`From`].
+///
+/// ```
+/// # extern crate vmm_serde;
+/// # use vmm_serde::{DeserializeState, SerializeState, Unseeded};
+/// # use std::time::Duration;
+/// #
+/// #[derive(SerializeState, DeserializeState)]
+/// struct Devices {
+///     // `Duration` only implements plain `Serialize`/`Deserialize`, so it can't be a `Vec`
+///     // element directly (unlike a direct field, `#[serde_state(skip)]` doesn't reach inside
+///     // a container); `Unseeded` bridges the gap.
+///     uptimes: Vec<Unseeded<Duration>>,
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Unseeded<T>(pub T);
+
+impl<T> From<T> for Unseeded<T> {
+    fn from(value: T) -> Self {
+        Unseeded(value)
+    }
+}
+
+impl<T> std::ops::Deref for Unseeded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> std::ops::DerefMut for Unseeded<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<Seed: ?Sized, T: Serialize> SerializeState<Seed> for Unseeded<T> {
+    fn serialize_state<S>(&self, serializer: S, _seed: &Seed) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        Serialize::serialize(&self.0, serializer)
+    }
+}
+
+impl<'de, Seed: ?Sized, T: Deserialize<'de>> DeserializeState<'de, Seed> for Unseeded<T> {
+    fn deserialize_state<D>(_seed: &mut Seed, deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Deserialize::deserialize(deserializer).map(Unseeded)
+    }
+}
+
+/// A `DeserializeSeed` that reborrows a `&mut Seed` for a single nested `DeserializeState` value.
+///
+/// This is what lets the container impls below, and `#[derive(DeserializeState)]`-generated code,
+/// drive serde's own `SeqAccess`/`Visitor` machinery (`next_element_seed`, `visit_seq`, ...) one
+/// element at a time while keeping `seed` mutable and shared across every element, instead of
+/// hand-rolling sequence parsing from scratch.
+#[doc(hidden)]
+pub struct DeserializeStateSeed<'a, Seed: ?Sized, T> {
+    seed: &'a mut Seed,
+    marker: PhantomData<T>,
+}
+
+impl<'a, Seed: ?Sized, T> DeserializeStateSeed<'a, Seed, T> {
+    /// Wrap `seed` for a single nested `DeserializeState` value.
+    pub fn new(seed: &'a mut Seed) -> Self {
+        DeserializeStateSeed {
+            seed,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, 'de, Seed: ?Sized, T: DeserializeState<'de, Seed>> DeserializeSeed<'de>
+    for DeserializeStateSeed<'a, Seed, T>
+{
+    type Value = T;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<T, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        T::deserialize_state(self.seed, deserializer)
+    }
+}
+
+/// Wraps a value alongside the `seed` needed to serialize it, so containers (and
+/// `#[derive(SerializeState)]`-generated code) can serialize their elements/fields through
+/// `SerializeState` while still using serde's own `SerializeSeq`/`SerializeTuple`/`SerializeStruct`.
+#[doc(hidden)]
+pub struct SerializeStateField<'a, Seed: ?Sized, T> {
+    value: &'a T,
+    seed: &'a Seed,
+}
+
+impl<'a, Seed: ?Sized, T> SerializeStateField<'a, Seed, T> {
+    /// Pair `value` with the `seed` needed to serialize it.
+    pub fn new(value: &'a T, seed: &'a Seed) -> Self {
+        SerializeStateField { value, seed }
+    }
+}
+
+impl<'a, Seed: ?Sized, T: SerializeState<Seed>> Serialize for SerializeStateField<'a, Seed, T> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.value.serialize_state(serializer, self.seed)
+    }
+}
+
+impl<Seed, T: SerializeState<Seed>> SerializeState<Seed> for Option<T> {
+    fn serialize_state<S>(&self, serializer: S, seed: &Seed) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            Some(value) => serializer.serialize_some(&SerializeStateField::new(value, seed)),
+            None => serializer.serialize_none(),
+        }
+    }
+}
+
+impl<'de, Seed, T: DeserializeState<'de, Seed>> DeserializeState<'de, Seed> for Option<T> {
+    fn deserialize_state<D>(seed: &mut Seed, deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct OptionVisitor<'a, Seed, T> {
+            seed: &'a mut Seed,
+            marker: PhantomData<T>,
+        }
+
+        impl<'a, 'de, Seed, T: DeserializeState<'de, Seed>> Visitor<'de> for OptionVisitor<'a, Seed, T> {
+            type Value = Option<T>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("option")
+            }
+
+            fn visit_none<E>(self) -> Result<Self::Value, E> {
+                Ok(None)
+            }
+
+            fn visit_some<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+            where
+                D: Deserializer<'de>,
+            {
+                DeserializeStateSeed::new(self.seed)
+                    .deserialize(deserializer)
+                    .map(Some)
+            }
+        }
+
+        deserializer.deserialize_option(OptionVisitor {
+            seed,
+            marker: PhantomData,
+        })
+    }
+}
+
+impl<Seed, T: SerializeState<Seed>> SerializeState<Seed> for Box<T> {
+    fn serialize_state<S>(&self, serializer: S, seed: &Seed) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        (**self).serialize_state(serializer, seed)
+    }
+}
+
+impl<'de, Seed, T: DeserializeState<'de, Seed>> DeserializeState<'de, Seed> for Box<T> {
+    fn deserialize_state<D>(seed: &mut Seed, deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Box::new(T::deserialize_state(seed, deserializer)?))
+    }
+}
+
+impl<Seed, T: SerializeState<Seed>> SerializeState<Seed> for Vec<T> {
+    fn serialize_state<S>(&self, serializer: S, seed: &Seed) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut seq = serializer.serialize_seq(Some(self.len()))?;
+        for value in self {
+            seq.serialize_element(&SerializeStateField::new(value, seed))?;
+        }
+        seq.end()
+    }
+}
+
+impl<'de, Seed, T: DeserializeState<'de, Seed>> DeserializeState<'de, Seed> for Vec<T> {
+    fn deserialize_state<D>(seed: &mut Seed, deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct VecVisitor<'a, Seed, T> {
+            seed: &'a mut Seed,
+            marker: PhantomData<T>,
+        }
+
+        impl<'a, 'de, Seed, T: DeserializeState<'de, Seed>> Visitor<'de> for VecVisitor<'a, Seed, T> {
+            type Value = Vec<T>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a sequence")
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                let mut values = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(value) =
+                    seq.next_element_seed(DeserializeStateSeed::new(&mut *self.seed))?
+                {
+                    values.push(value);
+                }
+                Ok(values)
+            }
+        }
+
+        deserializer.deserialize_seq(VecVisitor {
+            seed,
+            marker: PhantomData,
+        })
+    }
+}
+
+macro_rules! tuple_impl {
+    ($len:expr; $($idx:tt => $name:ident),+) => {
+        impl<Seed, $($name: SerializeState<Seed>),+> SerializeState<Seed> for ($($name,)+) {
+            fn serialize_state<S>(&self, serializer: S, seed: &Seed) -> Result<S::Ok, S::Error>
+            where
+                S: Serializer,
+            {
+                let mut tuple = serializer.serialize_tuple($len)?;
+                $(tuple.serialize_element(&SerializeStateField::new(&self.$idx, seed))?;)+
+                tuple.end()
+            }
+        }
+
+        impl<'de, Seed, $($name: DeserializeState<'de, Seed>),+> DeserializeState<'de, Seed> for ($($name,)+) {
+            fn deserialize_state<De>(seed: &mut Seed, deserializer: De) -> Result<Self, De::Error>
+            where
+                De: Deserializer<'de>,
+            {
+                struct TupleVisitor<'a, Seed, $($name),+> {
+                    seed: &'a mut Seed,
+                    marker: PhantomData<($($name,)+)>,
+                }
+
+                impl<'a, 'de, Seed, $($name: DeserializeState<'de, Seed>),+> Visitor<'de>
+                    for TupleVisitor<'a, Seed, $($name),+>
+                {
+                    type Value = ($($name,)+);
+
+                    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                        write!(formatter, "a tuple of size {}", $len)
+                    }
+
+                    fn visit_seq<SeqA>(self, mut seq: SeqA) -> Result<Self::Value, SeqA::Error>
+                    where
+                        SeqA: SeqAccess<'de>,
+                    {
+                        Ok(($(
+                            seq.next_element_seed(DeserializeStateSeed::<Seed, $name>::new(&mut *self.seed))?
+                                .ok_or_else(|| {
+                                    serde::de::Error::invalid_length($idx, &self)
+                                })?,
+                        )+))
+                    }
+                }
+
+                deserializer.deserialize_tuple(
+                    $len,
+                    TupleVisitor {
+                        seed,
+                        marker: PhantomData,
+                    },
+                )
+            }
+        }
+    };
+}
+
+tuple_impl!(1; 0 => A);
+tuple_impl!(2; 0 => A, 1 => B);
+tuple_impl!(3; 0 => A, 1 => B, 2 => C);
+tuple_impl!(4; 0 => A, 1 => B, 2 => C, 3 => D);
+
+#[cfg(test)]
+mod tests {
+    extern crate serde_json;
+    use super::*;
+    use std::cell::RefCell;
+
+    /// A toy fd-interning table: `serialize_state` only gets `&FdTable`, so interning uses
+    /// interior mutability; `deserialize_state` gets `&mut FdTable` and can push directly.
+    #[derive(Default)]
+    struct FdTable {
+        fds: RefCell<Vec<i32>>,
+    }
+
+    /// A file descriptor interned into the shared `FdTable` as it's (de)serialized, standing in
+    /// for the host->guest fd table the module docs describe.
+    struct Handle(i32);
+
+    impl SerializeState<FdTable> for Handle {
+        fn serialize_state<S>(&self, serializer: S, seed: &FdTable) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            seed.fds.borrow_mut().push(self.0);
+            self.0.serialize(serializer)
+        }
+    }
+
+    impl<'de> DeserializeState<'de, FdTable> for Handle {
+        fn deserialize_state<D>(seed: &mut FdTable, deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            let fd = i32::deserialize(deserializer)?;
+            seed.fds.borrow_mut().push(fd);
+            Ok(Handle(fd))
+        }
+    }
+
+    #[derive(vmm_serde::SerializeState, vmm_serde::DeserializeState)]
+    struct Device {
+        fd: Handle,
+        #[serde_state(skip)]
+        name: String,
+    }
+
+    #[test]
+    fn state_round_trip_interns_fd_through_seed() {
+        let devices = vec![
+            Device {
+                fd: Handle(10),
+                name: "eth0".to_string(),
+            },
+            Device {
+                fd: Handle(20),
+                name: "eth1".to_string(),
+            },
+        ];
+        let maybe_name: Option<String> = Some("snapshot-v1".to_string());
+
+        let serialize_seed = FdTable::default();
+        let mut buf = Vec::new();
+        {
+            let mut serializer = serde_json::Serializer::new(&mut buf);
+            devices
+                .serialize_state(&mut serializer, &serialize_seed)
+                .unwrap();
+        }
+        let mut name_buf = Vec::new();
+        {
+            let mut serializer = serde_json::Serializer::new(&mut name_buf);
+            maybe_name
+                .serialize_state(&mut serializer, &serialize_seed)
+                .unwrap();
+        }
+        assert_eq!(&*serialize_seed.fds.borrow(), &[10, 20]);
+
+        let mut deserialize_seed = FdTable::default();
+        let mut deserializer = serde_json::Deserializer::from_slice(&buf);
+        let decoded =
+            Vec::<Device>::deserialize_state(&mut deserialize_seed, &mut deserializer).unwrap();
+        let mut name_deserializer = serde_json::Deserializer::from_slice(&name_buf);
+        let decoded_name =
+            Option::<String>::deserialize_state(&mut deserialize_seed, &mut name_deserializer)
+                .unwrap();
+
+        assert_eq!(decoded[0].fd.0, 10);
+        assert_eq!(decoded[1].fd.0, 20);
+        assert_eq!(decoded[0].name, "eth0");
+        assert_eq!(decoded[1].name, "eth1");
+        assert_eq!(decoded_name, maybe_name);
+        assert_eq!(
+            &*deserialize_seed.fds.borrow(),
+            &*serialize_seed.fds.borrow(),
+            "seed must advance identically on both sides"
+        );
+    }
+}
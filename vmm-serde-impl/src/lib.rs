@@ -0,0 +1,195 @@
+// Copyright (C) 2019 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
+
+//! Procedural macros backing `vmm-serde`.
+//!
+//! This crate currently implements only `#[derive(SerializeState)]`/`#[derive(DeserializeState)]`,
+//! the derives for `vmm_serde::state`'s seeded (de)serialization traits. The other macros
+//! `vmm-serde` re-exports from here (`export_as_pub`, the plain `Serialize`/`Deserialize`
+//! passthroughs, `SerializeFfi`/`DeserializeFfi`/`DeserializeFfiFam`) are out of scope for this
+//! change and are not implemented here.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Ident, Index};
+
+/// Derive `vmm_serde::SerializeState<Seed>` for a struct with named fields.
+///
+/// Every field is serialized by calling `SerializeState::serialize_state` with the same `seed`,
+/// in declaration order, except fields marked `#[serde_state(skip)]`, which are serialized with
+/// plain `Serialize` instead.
+#[proc_macro_derive(SerializeState, attributes(serde_state))]
+pub fn derive_serialize_state(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = input.ident;
+    let fields = match named_fields(&input.data, &name) {
+        Ok(fields) => fields,
+        Err(err) => return err.to_compile_error().into(),
+    };
+
+    let len = fields.len();
+    let elements = fields.iter().map(|field| {
+        let ident = &field.ident;
+        if is_skipped(field) {
+            quote! { tuple.serialize_element(&self.#ident)?; }
+        } else {
+            quote! {
+                tuple.serialize_element(&::vmm_serde::SerializeStateField::new(&self.#ident, seed))?;
+            }
+        }
+    });
+    let where_clause = state_bounds(&fields, quote! { ::vmm_serde::SerializeState<Seed> });
+
+    let expanded = quote! {
+        const _: () = {
+            extern crate serde as _serde;
+
+            impl<Seed: ?Sized> ::vmm_serde::SerializeState<Seed> for #name #where_clause {
+                fn serialize_state<S>(&self, serializer: S, seed: &Seed) -> ::std::result::Result<S::Ok, S::Error>
+                where
+                    S: _serde::Serializer,
+                {
+                    use _serde::ser::SerializeTuple;
+                    let mut tuple = serializer.serialize_tuple(#len)?;
+                    #(#elements)*
+                    tuple.end()
+                }
+            }
+        };
+    };
+    expanded.into()
+}
+
+/// Derive `vmm_serde::DeserializeState<'de, Seed>` for a struct with named fields.
+///
+/// Every field is deserialized by calling `DeserializeState::deserialize_state` with the same
+/// `seed`, in the same declaration order `#[derive(SerializeState)]` serializes them in, except
+/// fields marked `#[serde_state(skip)]`, which are deserialized with plain `Deserialize` instead.
+#[proc_macro_derive(DeserializeState, attributes(serde_state))]
+pub fn derive_deserialize_state(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = input.ident;
+    let visitor = Ident::new(&format!("{}StateVisitor", name), Span::call_site());
+    let fields = match named_fields(&input.data, &name) {
+        Ok(fields) => fields,
+        Err(err) => return err.to_compile_error().into(),
+    };
+
+    let expecting = format!("struct {}", name);
+    let len = fields.len();
+    let field_idents: Vec<_> = fields.iter().map(|field| field.ident.clone()).collect();
+    let field_reads = fields.iter().enumerate().map(|(idx, field)| {
+        let ident = &field.ident;
+        let idx = Index::from(idx);
+        if is_skipped(field) {
+            quote! {
+                let #ident = seq
+                    .next_element()?
+                    .ok_or_else(|| _serde::de::Error::invalid_length(#idx, &self))?;
+            }
+        } else {
+            quote! {
+                let #ident = seq
+                    .next_element_seed(::vmm_serde::DeserializeStateSeed::new(&mut *self.seed))?
+                    .ok_or_else(|| _serde::de::Error::invalid_length(#idx, &self))?;
+            }
+        }
+    });
+    let where_clause = state_bounds(
+        &fields,
+        quote! { ::vmm_serde::DeserializeState<'de, Seed> },
+    );
+
+    let expanded = quote! {
+        const _: () = {
+            extern crate serde as _serde;
+
+            struct #visitor<'a, Seed: ?Sized> {
+                seed: &'a mut Seed,
+            }
+
+            impl<'a, 'de, Seed: ?Sized> _serde::de::Visitor<'de> for #visitor<'a, Seed> #where_clause {
+                type Value = #name;
+
+                fn expecting(&self, formatter: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                    formatter.write_str(#expecting)
+                }
+
+                fn visit_seq<A>(self, mut seq: A) -> ::std::result::Result<Self::Value, A::Error>
+                where
+                    A: _serde::de::SeqAccess<'de>,
+                {
+                    #(#field_reads)*
+                    Ok(#name { #(#field_idents),* })
+                }
+            }
+
+            impl<'de, Seed: ?Sized> ::vmm_serde::DeserializeState<'de, Seed> for #name #where_clause {
+                fn deserialize_state<D>(seed: &mut Seed, deserializer: D) -> ::std::result::Result<Self, D::Error>
+                where
+                    D: _serde::Deserializer<'de>,
+                {
+                    deserializer.deserialize_tuple(#len, #visitor { seed })
+                }
+            }
+        };
+    };
+    expanded.into()
+}
+
+/// Build a `where` clause requiring every non-skipped field's type to satisfy `bound`, so the
+/// generated impl only claims to support the `Seed` types its fields actually support, instead of
+/// an unconditional `impl<Seed> ... for #name` that fails to type-check as soon as a field's
+/// `SerializeState`/`DeserializeState` impl isn't itself generic over every `Seed`.
+fn state_bounds(
+    fields: &syn::punctuated::Punctuated<syn::Field, syn::token::Comma>,
+    bound: proc_macro2::TokenStream,
+) -> proc_macro2::TokenStream {
+    let predicates = fields.iter().filter(|field| !is_skipped(field)).map(|field| {
+        let ty = &field.ty;
+        quote! { #ty: #bound }
+    });
+    let predicates: Vec<_> = predicates.collect();
+    if predicates.is_empty() {
+        quote! {}
+    } else {
+        quote! { where #(#predicates),* }
+    }
+}
+
+fn named_fields<'a>(
+    data: &'a Data,
+    name: &Ident,
+) -> syn::Result<&'a syn::punctuated::Punctuated<syn::Field, syn::token::Comma>> {
+    match data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => Ok(&fields.named),
+            _ => Err(syn::Error::new(
+                name.span(),
+                "SerializeState/DeserializeState only support structs with named fields",
+            )),
+        },
+        _ => Err(syn::Error::new(
+            name.span(),
+            "SerializeState/DeserializeState only support structs with named fields",
+        )),
+    }
+}
+
+fn is_skipped(field: &syn::Field) -> bool {
+    field.attrs.iter().any(|attr| {
+        if !attr.path.is_ident("serde_state") {
+            return false;
+        }
+        let nested = match attr.parse_args_with(
+            syn::punctuated::Punctuated::<syn::Path, syn::token::Comma>::parse_terminated,
+        ) {
+            Ok(nested) => nested,
+            Err(_) => return false,
+        };
+        nested.iter().any(|path| path.is_ident("skip"))
+    })
+}